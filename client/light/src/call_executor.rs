@@ -18,14 +18,13 @@
 
 //! Methods that light client could use to execute runtime calls.
 
-use std::{cell::RefCell, panic::UnwindSafe, result, sync::Arc};
+use std::{cell::RefCell, sync::Arc};
 
-use codec::{Decode, Encode};
+use futures::future::BoxFuture;
 use hash_db::Hasher;
 use sp_core::{
 	convert_hash,
 	traits::{CodeExecutor, SpawnNamed},
-	NativeOrEncoded,
 };
 use sp_externalities::Extensions;
 use sp_runtime::{
@@ -34,7 +33,7 @@ use sp_runtime::{
 };
 use sp_state_machine::{
 	self, create_proof_check_backend, execution_proof_check_on_trie_backend,
-	Backend as StateBackend, ExecutionManager, ExecutionStrategy, OverlayedChanges, StorageProof,
+	Backend as StateBackend, OffchainOverlayedChanges, OverlayedChanges, StorageProof,
 };
 
 use sp_api::{ProofRecorder, StorageTransactionCache};
@@ -42,32 +41,53 @@ use sp_api::{ProofRecorder, StorageTransactionCache};
 use sp_blockchain::{Error as ClientError, Result as ClientResult};
 
 use sc_client_api::{
-	backend::RemoteBackend, call_executor::CallExecutor, light::RemoteCallRequest,
+	backend::RemoteBackend, call_executor::CallExecutor, execution_extensions::ExecutionExtensions,
+	light::RemoteCallRequest,
 };
-use sc_executor::{NativeVersion, RuntimeVersion};
+use sc_executor::RuntimeVersion;
+
+/// The context a call is made in on a light client.
+///
+/// A light client never has a native runtime to fall back to, so this replaces the
+/// on-chain/offchain execution strategy selection used on full nodes: it tells the
+/// configured externalities extensions whether the call is part of regular on-chain
+/// block import/execution, or an offchain operation such as an RPC or offchain worker
+/// call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallContext {
+	/// Call is a part of on-chain block execution.
+	Onchain,
+	/// Call is an offchain operation, e.g. triggered by an RPC or an offchain worker.
+	Offchain,
+}
 
 /// Call executor that is able to execute calls only on genesis state.
 ///
 /// Trying to execute call on non-genesis state leads to error.
-pub struct GenesisCallExecutor<B, L> {
+pub struct GenesisCallExecutor<B, L, Block: BlockT> {
 	backend: Arc<B>,
 	local: L,
+	execution_extensions: ExecutionExtensions<Block>,
 }
 
-impl<B, L> GenesisCallExecutor<B, L> {
+impl<B, L, Block: BlockT> GenesisCallExecutor<B, L, Block> {
 	/// Create new genesis call executor.
-	pub fn new(backend: Arc<B>, local: L) -> Self {
-		Self { backend, local }
+	pub fn new(backend: Arc<B>, local: L, execution_extensions: ExecutionExtensions<Block>) -> Self {
+		Self { backend, local, execution_extensions }
 	}
 }
 
-impl<B, L: Clone> Clone for GenesisCallExecutor<B, L> {
+impl<B, L: Clone, Block: BlockT> Clone for GenesisCallExecutor<B, L, Block> {
 	fn clone(&self) -> Self {
-		GenesisCallExecutor { backend: self.backend.clone(), local: self.local.clone() }
+		GenesisCallExecutor {
+			backend: self.backend.clone(),
+			local: self.local.clone(),
+			execution_extensions: self.execution_extensions.clone(),
+		}
 	}
 }
 
-impl<Block, B, Local> CallExecutor<Block> for GenesisCallExecutor<B, Local>
+impl<Block, B, Local> CallExecutor<Block> for GenesisCallExecutor<B, Local, Block>
 where
 	Block: BlockT,
 	B: RemoteBackend<Block>,
@@ -79,71 +99,68 @@ where
 
 	fn call(
 		&self,
-		id: &BlockId<Block>,
+		at_hash: Block::Hash,
 		method: &str,
 		call_data: &[u8],
-		strategy: ExecutionStrategy,
+		context: CallContext,
 		extensions: Option<Extensions>,
 	) -> ClientResult<Vec<u8>> {
-		match self.backend.is_local_state_available(id) {
-			true => self.local.call(id, method, call_data, strategy, extensions),
+		match self.backend.is_local_state_available(&BlockId::Hash(at_hash)) {
+			true => self.local.call(at_hash, method, call_data, context, extensions),
 			false => Err(ClientError::NotAvailableOnLightClient),
 		}
 	}
 
-	fn contextual_call<
-		EM: Fn(
-			Result<NativeOrEncoded<R>, Self::Error>,
-			Result<NativeOrEncoded<R>, Self::Error>,
-		) -> Result<NativeOrEncoded<R>, Self::Error>,
-		R: Encode + Decode + PartialEq,
-		NC: FnOnce() -> result::Result<R, sp_api::ApiError> + UnwindSafe,
-	>(
+	fn contextual_call(
 		&self,
-		at: &BlockId<Block>,
+		at_hash: Block::Hash,
 		method: &str,
 		call_data: &[u8],
 		changes: &RefCell<OverlayedChanges>,
+		offchain_changes: &RefCell<OffchainOverlayedChanges>,
 		_: Option<&RefCell<StorageTransactionCache<Block, B::State>>>,
-		_manager: ExecutionManager<EM>,
-		native_call: Option<NC>,
+		context: CallContext,
 		recorder: &Option<ProofRecorder<Block>>,
 		extensions: Option<Extensions>,
-	) -> ClientResult<NativeOrEncoded<R>>
-	where
-		ExecutionManager<EM>: Clone,
-	{
-		// there's no actual way/need to specify native/wasm execution strategy on light node
-		// => we can safely ignore passed values
-
-		match self.backend.is_local_state_available(at) {
-			true => CallExecutor::contextual_call::<
-				fn(
-					Result<NativeOrEncoded<R>, Local::Error>,
-					Result<NativeOrEncoded<R>, Local::Error>,
-				) -> Result<NativeOrEncoded<R>, Local::Error>,
-				_,
-				NC,
-			>(
-				&self.local,
-				at,
-				method,
-				call_data,
-				changes,
-				None,
-				ExecutionManager::NativeWhenPossible,
-				native_call,
-				recorder,
-				extensions,
-			)
-			.map_err(|e| ClientError::Execution(Box::new(e.to_string()))),
+	) -> ClientResult<Vec<u8>> {
+		match self.backend.is_local_state_available(&BlockId::Hash(at_hash)) {
+			true => self
+				.local
+				.contextual_call(
+					at_hash,
+					method,
+					call_data,
+					changes,
+					offchain_changes,
+					None,
+					context,
+					recorder,
+					extensions,
+				)
+				.map_err(|e| ClientError::Execution(Box::new(e.to_string()))),
+			false => Err(ClientError::NotAvailableOnLightClient),
+		}
+	}
+
+	fn execution_extensions(&self) -> &ExecutionExtensions<Block> {
+		&self.execution_extensions
+	}
+
+	fn runtime_version(&self, at_hash: Block::Hash) -> ClientResult<RuntimeVersion> {
+		match self.backend.is_local_state_available(&BlockId::Hash(at_hash)) {
+			true => self.local.runtime_version(at_hash),
 			false => Err(ClientError::NotAvailableOnLightClient),
 		}
 	}
 
-	fn runtime_version(&self, id: &BlockId<Block>) -> ClientResult<RuntimeVersion> {
-		match self.backend.is_local_state_available(id) {
-			true => self.local.runtime_version(id),
+	fn prove_execution(
+		&self,
+		at_hash: Block::Hash,
+		method: &str,
+		call_data: &[u8],
+	) -> ClientResult<(Vec<u8>, StorageProof)> {
+		match self.backend.is_local_state_available(&BlockId::Hash(at_hash)) {
+			true => self.local.prove_execution(at_hash, method, call_data),
 			false => Err(ClientError::NotAvailableOnLightClient),
 		}
 	}
@@ -152,21 +169,20 @@ where
 		&self,
 		_state: &sp_state_machine::TrieBackend<S, HashFor<Block>>,
 		_changes: &mut OverlayedChanges,
+		_offchain_changes: &mut OffchainOverlayedChanges,
 		_method: &str,
 		_call_data: &[u8],
 	) -> ClientResult<(Vec<u8>, StorageProof)> {
 		Err(ClientError::NotAvailableOnLightClient)
 	}
-
-	fn native_runtime_version(&self) -> Option<&NativeVersion> {
-		None
-	}
 }
 
 /// Prove contextual execution using given block header in environment.
 ///
 /// Method is executed using passed header as environment' current block.
 /// Proof includes both environment preparation proof and method execution proof.
+/// Both the on-chain and offchain overlays are populated, so that methods relying
+/// on offchain-indexed storage can be proven too.
 pub fn prove_execution<Block, S, E>(
 	mut state: S,
 	executor: &E,
@@ -184,15 +200,22 @@ where
 	})?;
 
 	// execute method + record execution proof
-	let (result, exec_proof) =
-		executor.prove_at_trie_state(&trie_state, &mut Default::default(), method, call_data)?;
+	let (result, exec_proof) = executor.prove_at_trie_state(
+		&trie_state,
+		&mut Default::default(),
+		&mut Default::default(),
+		method,
+		call_data,
+	)?;
 
 	Ok((result, exec_proof))
 }
 
 /// Check remote contextual execution proof using given backend.
 ///
-/// Proof should include the method execution proof.
+/// Proof should include the method execution proof. The method is executed with
+/// both an on-chain and an offchain overlay (each starting empty), so that
+/// offchain-indexed storage reads are covered by the check.
 pub fn check_execution_proof<Header, E, H>(
 	executor: &E,
 	spawn_handle: Box<dyn SpawnNamed>,
@@ -210,6 +233,7 @@ where
 
 	// prepare execution environment
 	let mut changes = OverlayedChanges::default();
+	let mut offchain_changes = OffchainOverlayedChanges::default();
 	let trie_backend = create_proof_check_backend(root, remote_proof)?;
 
 	// TODO: Remove when solved: https://github.com/paritytech/substrate/issues/5047
@@ -222,6 +246,7 @@ where
 	execution_proof_check_on_trie_backend::<H, Header::Number, _, _>(
 		&trie_backend,
 		&mut changes,
+		&mut offchain_changes,
 		executor,
 		spawn_handle,
 		&request.method,
@@ -230,3 +255,192 @@ where
 	)
 	.map_err(Into::into)
 }
+
+/// Forwards to a shared, cheaply-clonable spawn handle.
+///
+/// Lets a single `Arc<dyn SpawnNamed>` be reused across the per-request calls made by
+/// `check_execution_proofs`, where each call still expects to consume an owned
+/// `Box<dyn SpawnNamed>`.
+#[derive(Clone)]
+struct SharedSpawnHandle(Arc<dyn SpawnNamed>);
+
+impl SpawnNamed for SharedSpawnHandle {
+	fn spawn_blocking(&self, name: &'static str, future: BoxFuture<'static, ()>) {
+		self.0.spawn_blocking(name, future)
+	}
+
+	fn spawn(&self, name: &'static str, future: BoxFuture<'static, ()>) {
+		self.0.spawn(name, future)
+	}
+}
+
+/// Check remote contextual execution proof for a batch of requests sharing the same
+/// block header, against a single storage proof covering all of them.
+///
+/// All requests are expected to have been made against the same block, so their
+/// proofs can be merged by the caller (e.g. a coalescing `on-demand` request queue)
+/// into one `remote_proof`. The proof-check trie backend and the runtime code are
+/// then built only once and reused for every request, which avoids re-decoding the
+/// trie nodes the requests have in common.
+pub fn check_execution_proofs<Header, E, H>(
+	executor: &E,
+	spawn_handle: Arc<dyn SpawnNamed>,
+	requests: &[RemoteCallRequest<Header>],
+	remote_proof: StorageProof,
+) -> ClientResult<Vec<ClientResult<Vec<u8>>>>
+where
+	Header: HeaderT,
+	E: CodeExecutor + Clone + 'static,
+	H: Hasher,
+	H::Out: Ord + codec::Codec + 'static,
+{
+	let request = match requests.first() {
+		Some(request) => request,
+		None => return Ok(Vec::new()),
+	};
+
+	if requests.iter().any(|r| r.block != request.block) {
+		return Err(ClientError::Backend(
+			"all requests in a batch must share the same block".into(),
+		))
+	}
+
+	let local_state_root = request.header.state_root();
+	let root: H::Out = convert_hash(&local_state_root);
+
+	// prepare the shared execution environment once for the whole batch
+	let trie_backend = create_proof_check_backend(root, remote_proof)?;
+
+	// TODO: Remove when solved: https://github.com/paritytech/substrate/issues/5047
+	let backend_runtime_code = sp_state_machine::backend::BackendRuntimeCode::new(&trie_backend);
+	let runtime_code = backend_runtime_code
+		.runtime_code()
+		.map_err(|_e| ClientError::RuntimeCodeMissing)?;
+
+	Ok(requests
+		.iter()
+		.map(|request| {
+			let mut changes = OverlayedChanges::default();
+			let mut offchain_changes = OffchainOverlayedChanges::default();
+
+			execution_proof_check_on_trie_backend::<H, Header::Number, _, _>(
+				&trie_backend,
+				&mut changes,
+				&mut offchain_changes,
+				executor,
+				Box::new(SharedSpawnHandle(spawn_handle.clone())),
+				&request.method,
+				&request.call_data,
+				&runtime_code,
+			)
+			.map_err(Into::into)
+		})
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::{storage::well_known_keys::CODE, testing::TaskExecutor, H256};
+	use sp_runtime::traits::BlakeTwo256;
+	use sp_trie::{trie_types::TrieDBMut, MemoryDB, TrieMut};
+
+	#[derive(Clone)]
+	struct DummyCodeExecutor(&'static [u8]);
+
+	impl CodeExecutor for DummyCodeExecutor {
+		type Error = String;
+
+		fn call(
+			&self,
+			_ext: &mut dyn sp_externalities::Externalities,
+			_runtime_code: &sp_core::traits::RuntimeCode,
+			_method: &str,
+			_data: &[u8],
+		) -> (Result<Vec<u8>, Self::Error>, bool) {
+			(Ok(self.0.to_vec()), true)
+		}
+	}
+
+	// Builds a proof-check-ready storage proof that contains just a `:code` entry, which
+	// is all `check_execution_proofs` needs to read before handing control to the
+	// (dummy, in these tests) code executor.
+	fn code_proof(code: &[u8]) -> (H256, StorageProof) {
+		let mut db = MemoryDB::<BlakeTwo256>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = TrieDBMut::<BlakeTwo256>::new(&mut db, &mut root);
+			trie.insert(CODE, code).expect("inserting :code key into trie");
+		}
+
+		let backend = sp_state_machine::TrieBackend::new(db, root);
+		let proof = sp_state_machine::prove_read(backend, vec![CODE.to_vec()])
+			.expect("building storage proof for :code key");
+
+		(root, proof)
+	}
+
+	fn request(block: u64, state_root: H256) -> RemoteCallRequest<sp_runtime::testing::Header> {
+		RemoteCallRequest {
+			block: H256::from_low_u64_be(block),
+			header: sp_runtime::testing::Header::new(
+				block,
+				Default::default(),
+				state_root,
+				Default::default(),
+				Default::default(),
+			),
+			method: "Core_version".into(),
+			call_data: Vec::new(),
+			retry_count: None,
+		}
+	}
+
+	#[test]
+	fn check_execution_proofs_returns_empty_vec_for_empty_batch() {
+		let (_, proof) = code_proof(&[]);
+		let result = check_execution_proofs::<sp_runtime::testing::Header, _, BlakeTwo256>(
+			&DummyCodeExecutor(&[]),
+			Arc::new(TaskExecutor::new()),
+			&[],
+			proof,
+		);
+
+		assert!(result.unwrap().is_empty());
+	}
+
+	#[test]
+	fn check_execution_proofs_rejects_requests_from_different_blocks() {
+		let (root, proof) = code_proof(&[]);
+		let requests = [request(1, root), request(2, root)];
+
+		let result = check_execution_proofs::<_, _, BlakeTwo256>(
+			&DummyCodeExecutor(&[]),
+			Arc::new(TaskExecutor::new()),
+			&requests,
+			proof,
+		);
+
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn check_execution_proofs_verifies_every_request_against_the_shared_proof() {
+		let dummy_result = b"dummy-result".to_vec();
+		let (root, proof) = code_proof(b"dummy-runtime-code");
+		let requests = [request(1, root), request(1, root)];
+
+		let result = check_execution_proofs::<_, _, BlakeTwo256>(
+			&DummyCodeExecutor(b"dummy-result"),
+			Arc::new(TaskExecutor::new()),
+			&requests,
+			proof,
+		)
+		.expect("same-block batch is accepted");
+
+		assert_eq!(result.len(), 2);
+		for call_result in result {
+			assert_eq!(call_result.unwrap(), dummy_result);
+		}
+	}
+}